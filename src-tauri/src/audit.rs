@@ -0,0 +1,31 @@
+// Tamper-audit trail for `native_pdf_patch`.
+//
+// Every applied replacement is recorded with enough detail (page, operator,
+// before/after text, byte offset) to document exactly what changed.
+// `verify_pdf_patch` uses that record to independently re-derive the
+// patched output from the *original* source bytes - re-applying each
+// recorded edit at its exact recorded position rather than re-running rule
+// matching, which would have to reproduce the original request's rule
+// ordering and per-rule caps exactly to avoid false mismatches. The replay
+// runs lopdf directly on the source bytes, so an output that was patched
+// from qpdf- or mutool-normalized bytes is not reproduced and does not
+// verify.
+
+use sha2::{Digest, Sha256};
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// One applied replacement, recorded for the audit trail.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplacementRecord {
+    pub page_number: u32,
+    pub operator: String,
+    pub before_text: String,
+    pub after_text: String,
+    pub byte_offset: usize,
+}
@@ -0,0 +1,69 @@
+// Resolves a page's `/Contents` entry into the actual stream objects behind
+// it, so the patch loop can edit each physical stream in place instead of
+// flattening a multi-stream page into one merged stream the way
+// `Document::get_page_content` does. Preserving the original stream objects
+// also means we don't leave orphaned, no-longer-referenced streams behind in
+// the saved document.
+
+use lopdf::{Document, Object, ObjectId};
+
+/// Returns the object ids of the stream(s) backing a page's `/Contents`,
+/// whether it's a single stream reference or an array of them (the form
+/// producers use to let independent tools append to a page without
+/// re-serializing the whole content stream). A page with no `/Contents` at
+/// all (a valid, blank page) yields an empty `Vec` rather than an error, so
+/// one such page doesn't abort patching the rest of the document.
+pub fn page_content_stream_ids(doc: &Document, page_id: ObjectId) -> Result<Vec<ObjectId>, String> {
+    let page_dict = doc
+        .get_dictionary(page_id)
+        .map_err(|e| format!("Failed to read page object: {e}"))?;
+    let Ok(contents) = page_dict.get(b"Contents") else {
+        return Ok(Vec::new());
+    };
+    match contents {
+        Object::Reference(id) => Ok(vec![*id]),
+        Object::Array(items) => Ok(items
+            .iter()
+            .filter_map(|o| match o {
+                Object::Reference(id) => Some(*id),
+                _ => None,
+            })
+            .collect()),
+        other => Err(format!(
+            "Unsupported /Contents type {other:?} (expected a stream reference or array of references)"
+        )),
+    }
+}
+
+/// Reads and decompresses the content stream at `stream_id`. Returns `Ok(None)`
+/// rather than an error when the object isn't actually a stream (a malformed
+/// or unusual `/Contents` array entry), so one such entry is skipped instead
+/// of aborting the whole request - consistent with `page_content_stream_ids`
+/// already filtering non-reference array entries out upstream.
+pub fn decoded_stream_content(doc: &Document, stream_id: ObjectId) -> Result<Option<Vec<u8>>, String> {
+    let object = doc
+        .get_object(stream_id)
+        .map_err(|e| format!("Failed to read content stream object: {e}"))?;
+    let Ok(stream) = object.as_stream() else {
+        return Ok(None);
+    };
+    stream
+        .decompressed_content()
+        .map(Some)
+        .map_err(|e| format!("Failed to decompress content stream: {e}"))
+}
+
+/// Writes re-encoded content-stream operations back into the stream object
+/// at `stream_id`, replacing its (now stale) compressed representation.
+pub fn write_stream_content(doc: &mut Document, stream_id: ObjectId, content: Vec<u8>) -> Result<(), String> {
+    let object = doc
+        .get_object_mut(stream_id)
+        .map_err(|e| format!("Failed to access content stream object: {e}"))?;
+    let stream = object
+        .as_stream_mut()
+        .map_err(|e| format!("/Contents entry is not a stream: {e}"))?;
+    stream.set_plain_content(content);
+    stream.dict.remove(b"Filter");
+    stream.dict.remove(b"DecodeParms");
+    Ok(())
+}
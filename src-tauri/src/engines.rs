@@ -0,0 +1,157 @@
+// Selectable external patch backends for `native_pdf_patch`.
+//
+// lopdf is fast and dependency-free but, like most pure-Rust PDF libraries,
+// struggles with some producer quirks (heavily compressed content streams,
+// linearized files). qpdf and mutool are both very good at normalizing a
+// PDF into a form lopdf can reliably parse, so when one is available we
+// shell out to it as a preprocessing step and then run the same lopdf-based
+// patch logic on the normalized bytes.
+
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const EXTERNAL_ENGINE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Which backend should handle a `native_pdf_patch` request.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PatchEngine {
+    /// Try lopdf directly; if it replaces nothing, fall back to whichever
+    /// external tool is available.
+    Auto,
+    Lopdf,
+    Qpdf,
+    Mutool,
+}
+
+impl PatchEngine {
+    pub fn parse(value: Option<&str>) -> Result<PatchEngine, String> {
+        match value.unwrap_or("auto") {
+            "auto" => Ok(PatchEngine::Auto),
+            "lopdf" => Ok(PatchEngine::Lopdf),
+            "qpdf" => Ok(PatchEngine::Qpdf),
+            "mutool" => Ok(PatchEngine::Mutool),
+            other => Err(format!(
+                "Unknown engine \"{other}\" (expected \"auto\", \"lopdf\", \"qpdf\", or \"mutool\")"
+            )),
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            PatchEngine::Auto => "auto",
+            PatchEngine::Lopdf => "lopdf-native-object-patch",
+            PatchEngine::Qpdf => "qpdf-normalize+lopdf-patch",
+            PatchEngine::Mutool => "mutool-normalize+lopdf-patch",
+        }
+    }
+}
+
+/// Runs `qpdf` over `bytes` to decompress object/stream compression,
+/// returning the normalized PDF bytes lopdf can then patch.
+pub fn normalize_with_qpdf(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    normalize_with_tool(
+        "qpdf",
+        &["--object-streams=disable", "--stream-data=uncompress", "--decode-level=all"],
+        bytes,
+    )
+}
+
+/// Runs `mutool clean` over `bytes`, which similarly decompresses streams
+/// and repairs broken cross-reference tables.
+pub fn normalize_with_mutool(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    normalize_with_tool("mutool", &["clean", "-d", "-a"], bytes)
+}
+
+fn normalize_with_tool(program: &str, base_args: &[&str], bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let input = tempfile_path(program, "in");
+    let output = tempfile_path(program, "out");
+    std::fs::write(&input, bytes).map_err(|e| format!("Failed to write temp input for {program}: {e}"))?;
+
+    let mut args: Vec<&str> = base_args.to_vec();
+    let input_str = input.to_string_lossy().to_string();
+    let output_str = output.to_string_lossy().to_string();
+    args.push(&input_str);
+    args.push(&output_str);
+
+    let result = run_with_timeout(program, &args, EXTERNAL_ENGINE_TIMEOUT);
+
+    let cleanup = |p: &std::path::Path| {
+        let _ = std::fs::remove_file(p);
+    };
+
+    let output_bytes = match result {
+        Ok(run) if run.status.success() => std::fs::read(&output)
+            .map_err(|e| format!("{program} reported success but produced no output: {e}")),
+        Ok(run) => Err(format!(
+            "{program} exited with {status}: {stderr}",
+            status = run.status,
+            stderr = String::from_utf8_lossy(&run.stderr).trim()
+        )),
+        Err(e) => Err(format!("Failed to run {program}: {e}")),
+    };
+
+    cleanup(&input);
+    cleanup(&output);
+    output_bytes
+}
+
+/// Process-wide counter making each temp path unique per call, not just per
+/// process: two concurrent `native_pdf_patch` invocations using the same
+/// tool (an explicit `qpdf` request racing an `auto`-fallback to `qpdf`, for
+/// instance) run in the same process and share a pid, so pid alone isn't
+/// enough to keep their temp files from colliding.
+static NEXT_TEMPFILE_ID: AtomicU64 = AtomicU64::new(0);
+
+fn tempfile_path(program: &str, suffix: &str) -> std::path::PathBuf {
+    let id = NEXT_TEMPFILE_ID.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!(
+        "trust-lens-{program}-{pid}-{id}-{suffix}.pdf",
+        pid = std::process::id()
+    ))
+}
+
+struct ToolOutput {
+    status: std::process::ExitStatus,
+    stderr: Vec<u8>,
+}
+
+/// Spawns `program` with `args`, capturing stdout/stderr, and kills it if it
+/// hasn't finished within `timeout`.
+fn run_with_timeout(program: &str, args: &[&str], timeout: Duration) -> Result<ToolOutput, String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to spawn {program}: {e}"))?;
+
+    let pid = child.id();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(child.wait_with_output());
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(Ok(output)) => Ok(ToolOutput {
+            status: output.status,
+            stderr: output.stderr,
+        }),
+        Ok(Err(e)) => Err(format!("{program} process error: {e}")),
+        Err(_) => {
+            kill_pid(pid);
+            Err(format!("{program} timed out after {}s", timeout.as_secs()))
+        }
+    }
+}
+
+fn kill_pid(pid: u32) {
+    if cfg!(target_os = "windows") {
+        let _ = Command::new("taskkill").args(["/PID", &pid.to_string(), "/F"]).output();
+    } else {
+        let _ = Command::new("kill").args(["-9", &pid.to_string()]).output();
+    }
+}
@@ -0,0 +1,375 @@
+// Cross-operand text matching.
+//
+// A search term is frequently split across several `Object::String` entries
+// inside one `TJ` array (kerning pairs) or across consecutive `Tj`/`'`/`"`
+// operators, so matching each operand in isolation misses it entirely. This
+// module concatenates a content stream's decoded show-text into one logical
+// string, matches against that, and writes the result back into whichever
+// operands it came from - splitting an operand's text when a match starts
+// partway through it, and dropping `TJ` array entries that end up empty.
+
+use crate::audit::ReplacementRecord;
+use crate::font_encoding::{self, FontEncodingMap};
+use crate::replacement_rules::{CompiledRule, MatchInstance};
+use lopdf::content::Content;
+use lopdf::Object;
+use std::collections::HashMap;
+use std::ops::Range;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    /// The string operand of a `Tj`/`'`/`"` operator (always its last one:
+    /// `"` takes word and character spacing before the string).
+    Direct,
+    /// The string at this index within a `TJ` array.
+    Array(usize),
+}
+
+struct Chunk<'a> {
+    op_idx: usize,
+    slot: Slot,
+    range: Range<usize>,
+    font: Option<&'a FontEncodingMap>,
+}
+
+#[derive(Default)]
+pub struct RuleOutcome {
+    pub hits: usize,
+    pub encoded_hits: usize,
+    pub raw_hits: usize,
+    /// Matches found but left unapplied because the text they'd produce
+    /// couldn't be encoded back into a multi-byte (Type0/CID) font's code
+    /// space; writing raw UTF-8 bytes there would corrupt the glyph stream,
+    /// so the operand is left untouched instead.
+    pub skipped_hits: usize,
+}
+
+/// Per-op edits collected while rewriting chunks, plus the logical-match
+/// counts they represent. Direct slots get a single new byte string; Array
+/// slots collect (item_idx, Option<new_bytes>) pairs, `None` meaning "drop
+/// this array entry, its text was entirely absorbed into an earlier one".
+struct AppliedEdits {
+    direct_edits: HashMap<usize, Vec<u8>>,
+    array_edits: HashMap<usize, Vec<(usize, Option<Vec<u8>>)>>,
+    encoded_hits: usize,
+    raw_hits: usize,
+    skipped_hits: usize,
+    /// Parallel to the `matches` slice passed in: `true` for a match whose
+    /// starting chunk turned out unencodable, so nothing was written for it.
+    unencodable: Vec<bool>,
+}
+
+/// Applies a single compiled rule against the flattened text of every
+/// show-text operator in `content`, rewriting the originating operands in
+/// place. Call once per rule, in order, against the same `content` so later
+/// rules see the effect of earlier ones. `budget` caps how many more
+/// instances this rule is allowed to replace (its `max_replacements`, minus
+/// however many it has already used in other content streams); `None` means
+/// unlimited. `page_number` is recorded on every audit record this call
+/// produces; it's otherwise opaque to the matching logic.
+///
+/// Returns the outcome alongside one `ReplacementRecord` per match, built
+/// from the pre-rewrite operator and text so the audit trail reflects what
+/// was actually found, not what the content stream looks like afterwards.
+pub fn apply_rule(
+    content: &mut Content,
+    fonts: &HashMap<Vec<u8>, FontEncodingMap>,
+    rule: &CompiledRule,
+    budget: Option<usize>,
+    page_number: u32,
+) -> (RuleOutcome, Vec<ReplacementRecord>) {
+    let (flattened, chunks) = flatten(content, fonts);
+    let matches = rule.find_matches(&flattened, budget);
+    if matches.is_empty() {
+        return (RuleOutcome::default(), Vec::new());
+    }
+
+    let AppliedEdits {
+        direct_edits,
+        array_edits,
+        encoded_hits,
+        raw_hits,
+        skipped_hits,
+        unencodable,
+    } = rewrite_chunks(&chunks, &flattened, &matches);
+
+    let outcome = RuleOutcome {
+        hits: encoded_hits + raw_hits,
+        encoded_hits,
+        raw_hits,
+        skipped_hits,
+    };
+
+    let records = matches
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !unencodable[*i])
+        .map(|(_, m)| ReplacementRecord {
+            page_number,
+            operator: containing_chunk(&chunks, m.range.start)
+                .map(|chunk| content.operations[chunk.op_idx].operator.clone())
+                .unwrap_or_default(),
+            before_text: flattened[m.range.clone()].to_string(),
+            after_text: m.replacement.clone(),
+            byte_offset: m.range.start,
+        })
+        .collect();
+
+    apply_edits_to_content(content, direct_edits, array_edits);
+
+    (outcome, records)
+}
+
+/// Re-applies a previously recorded audit trail to `content` verbatim,
+/// instead of re-running the rule that produced it. Each record already
+/// names the exact page, operator, byte offset and before/after text it
+/// replaced, so this locates that exact spot and substitutes `after_text`
+/// for `before_text` there; it doesn't re-derive matches by searching,
+/// which would have to reproduce the original request's rule ordering and
+/// `max_replacements` caps exactly to avoid false mismatches.
+///
+/// Records for a different page, or whose recorded text/operator no longer
+/// matches what's actually at that byte offset in `content` (wrong source
+/// document, or tampering), are silently skipped rather than erroring -
+/// the caller compares the returned count against `records.len()` to decide
+/// whether every record was actually found somewhere in the document.
+pub fn apply_audit_records(
+    content: &mut Content,
+    fonts: &HashMap<Vec<u8>, FontEncodingMap>,
+    page_number: u32,
+    records: &[ReplacementRecord],
+) -> usize {
+    let (flattened, chunks) = flatten(content, fonts);
+    let matches: Vec<MatchInstance> = records
+        .iter()
+        .filter(|r| r.page_number == page_number)
+        .filter_map(|r| {
+            let range = r.byte_offset..r.byte_offset.checked_add(r.before_text.len())?;
+            if flattened.get(range.clone()) != Some(r.before_text.as_str()) {
+                return None;
+            }
+            let operator_matches = containing_chunk(&chunks, range.start)
+                .map(|c| content.operations[c.op_idx].operator == r.operator)
+                .unwrap_or(false);
+            operator_matches.then_some(MatchInstance {
+                range,
+                replacement: r.after_text.clone(),
+            })
+        })
+        .collect();
+    if matches.is_empty() {
+        return 0;
+    }
+
+    let AppliedEdits {
+        direct_edits,
+        array_edits,
+        encoded_hits,
+        raw_hits,
+        ..
+    } = rewrite_chunks(&chunks, &flattened, &matches);
+
+    apply_edits_to_content(content, direct_edits, array_edits);
+    encoded_hits + raw_hits
+}
+
+/// Rewrites every chunk touched by `matches`, collecting the resulting
+/// per-operand edits and logical-match counts. A chunk may contain more
+/// than one match's start (e.g. a short repeated term within one `Tj`
+/// string), so counts are incremented by how many match starts the chunk
+/// actually contains, not by a flat one-per-chunk.
+fn rewrite_chunks(chunks: &[Chunk], flattened: &str, matches: &[MatchInstance]) -> AppliedEdits {
+    let mut direct_edits: HashMap<usize, Vec<u8>> = HashMap::new();
+    let mut array_edits: HashMap<usize, Vec<(usize, Option<Vec<u8>>)>> = HashMap::new();
+    let mut encoded_hits = 0usize;
+    let mut raw_hits = 0usize;
+    let mut skipped_hits = 0usize;
+    let mut unencodable = vec![false; matches.len()];
+
+    for chunk in chunks {
+        let original = &flattened[chunk.range.clone()];
+        let rewritten = rewrite_chunk(chunk.range.start, original, matches);
+        if rewritten == original {
+            continue;
+        }
+        let touching_indices: Vec<usize> = matches
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.range.start >= chunk.range.start && m.range.start < chunk.range.end)
+            .map(|(i, _)| i)
+            .collect();
+
+        let encoded_bytes = chunk.font.and_then(|f| f.encode(&rewritten));
+        if encoded_bytes.is_none() && chunk.font.map(FontEncodingMap::is_multi_byte).unwrap_or(false) {
+            // A 2-byte CID font that can't encode the replacement text: a
+            // raw-UTF-8 fallback would inject 1-byte-per-char text into a
+            // 2-byte code space and corrupt the glyph stream, so leave this
+            // operand untouched rather than emit broken bytes.
+            skipped_hits += touching_indices.len();
+            for i in touching_indices {
+                unencodable[i] = true;
+            }
+            continue;
+        }
+        let (bytes, encoded) = match encoded_bytes {
+            Some(encoded_bytes) => (encoded_bytes, true),
+            None => (rewritten.clone().into_bytes(), false),
+        };
+        if encoded {
+            encoded_hits += touching_indices.len();
+        } else {
+            raw_hits += touching_indices.len();
+        }
+        match chunk.slot {
+            Slot::Direct => {
+                direct_edits.insert(chunk.op_idx, bytes);
+            }
+            Slot::Array(item_idx) => {
+                let new_entry = if rewritten.is_empty() { None } else { Some(bytes) };
+                array_edits.entry(chunk.op_idx).or_default().push((item_idx, new_entry));
+            }
+        }
+    }
+
+    AppliedEdits {
+        direct_edits,
+        array_edits,
+        encoded_hits,
+        raw_hits,
+        skipped_hits,
+        unencodable,
+    }
+}
+
+/// Writes collected per-operand edits back into `content`'s operations.
+fn apply_edits_to_content(
+    content: &mut Content,
+    mut direct_edits: HashMap<usize, Vec<u8>>,
+    mut array_edits: HashMap<usize, Vec<(usize, Option<Vec<u8>>)>>,
+) {
+    for (op_idx, op) in content.operations.iter_mut().enumerate() {
+        if let Some(bytes) = direct_edits.remove(&op_idx) {
+            if let Some(Object::String(raw, _)) = op.operands.last_mut() {
+                *raw = bytes;
+            }
+        }
+        if let Some(mut edits) = array_edits.remove(&op_idx) {
+            if let Some(Object::Array(items)) = op.operands.get_mut(0) {
+                // Apply highest index first so earlier removals don't shift
+                // the indices of edits still to be applied.
+                edits.sort_by(|a, b| b.0.cmp(&a.0));
+                for (item_idx, new_entry) in edits {
+                    match new_entry {
+                        Some(bytes) => {
+                            if let Some(Object::String(raw, _)) = items.get_mut(item_idx) {
+                                *raw = bytes;
+                            }
+                        }
+                        None => {
+                            if item_idx < items.len() {
+                                items.remove(item_idx);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Finds the chunk containing byte offset `at` in the flattened text, used
+/// to recover which operator produced the text at a match's start.
+fn containing_chunk<'a, 'b>(chunks: &'b [Chunk<'a>], at: usize) -> Option<&'b Chunk<'a>> {
+    chunks.iter().find(|c| c.range.start <= at && at < c.range.end)
+}
+
+fn flatten<'a>(content: &Content, fonts: &'a HashMap<Vec<u8>, FontEncodingMap>) -> (String, Vec<Chunk<'a>>) {
+    let mut flattened = String::new();
+    let mut chunks = Vec::new();
+    let mut current_font: Option<&FontEncodingMap> = None;
+
+    for (op_idx, op) in content.operations.iter().enumerate() {
+        match op.operator.as_str() {
+            "Tf" => {
+                current_font = match op.operands.first() {
+                    Some(Object::Name(name)) => fonts.get(name),
+                    _ => None,
+                };
+            }
+            "Tj" | "'" | "\"" => {
+                if let Some(Object::String(raw, _)) = op.operands.last() {
+                    push_chunk(&mut flattened, &mut chunks, op_idx, Slot::Direct, raw, current_font);
+                }
+            }
+            "TJ" => {
+                if let Some(Object::Array(items)) = op.operands.first() {
+                    for (item_idx, entry) in items.iter().enumerate() {
+                        if let Object::String(raw, _) = entry {
+                            push_chunk(&mut flattened, &mut chunks, op_idx, Slot::Array(item_idx), raw, current_font);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    (flattened, chunks)
+}
+
+fn push_chunk<'a>(
+    flattened: &mut String,
+    chunks: &mut Vec<Chunk<'a>>,
+    op_idx: usize,
+    slot: Slot,
+    raw: &[u8],
+    font: Option<&'a FontEncodingMap>,
+) {
+    let text = font_encoding::decode_with_fallback(raw, font);
+    if text.is_empty() {
+        return;
+    }
+    let start = flattened.len();
+    flattened.push_str(&text);
+    chunks.push(Chunk {
+        op_idx,
+        slot,
+        range: start..flattened.len(),
+        font,
+    });
+}
+
+/// Rebuilds one chunk's text: unmatched characters pass through unchanged,
+/// and each match overlapping this chunk is either replaced with its
+/// (already capture-group-expanded) replacement text, if the match starts
+/// inside this chunk, or dropped entirely, if the match started in an
+/// earlier chunk and just trails off into this one - so a multi-chunk
+/// match's replacement text appears exactly once.
+fn rewrite_chunk(chunk_start: usize, original: &str, matches: &[MatchInstance]) -> String {
+    let chunk_end = chunk_start + original.len();
+    let mut overlaps: Vec<(Range<usize>, Option<&str>)> = matches
+        .iter()
+        .filter(|m| m.range.start < chunk_end && m.range.end > chunk_start)
+        .map(|m| {
+            let local_start = m.range.start.saturating_sub(chunk_start).min(original.len());
+            let local_end = (m.range.end.min(chunk_end) - chunk_start).min(original.len());
+            let starts_here = m.range.start >= chunk_start;
+            (local_start..local_end, starts_here.then_some(m.replacement.as_str()))
+        })
+        .collect();
+    if overlaps.is_empty() {
+        return original.to_string();
+    }
+    overlaps.sort_by_key(|(r, _)| r.start);
+
+    let mut out = String::with_capacity(original.len());
+    let mut cursor = 0usize;
+    for (range, replacement) in overlaps {
+        out.push_str(&original[cursor..range.start]);
+        if let Some(replacement) = replacement {
+            out.push_str(replacement);
+        }
+        cursor = range.end;
+    }
+    out.push_str(&original[cursor..]);
+    out
+}
@@ -0,0 +1,327 @@
+// Per-font code<->Unicode resolution for content-stream text operators.
+//
+// PDFs are free to show text under any font encoding: a simple font with a
+// `/Differences` array remapping single bytes to arbitrary glyphs, or a
+// Type0/CID font (commonly Identity-H) where each glyph is addressed by a
+// 2-byte code that only makes sense via that font's `/ToUnicode` CMap.
+// The matching pass in flatten_match.rs needs Unicode text to match search terms
+// against, and needs to re-encode any replacement back into the same code
+// space it came from.
+
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::HashMap;
+
+/// Resolved code<->Unicode mapping for a single font resource.
+pub struct FontEncodingMap {
+    /// Number of bytes per character code (1 for simple fonts, 2 for the
+    /// Identity-H/V CID fonts this crate supports).
+    code_bytes: u8,
+    code_to_unicode: HashMap<u32, String>,
+    unicode_to_code: HashMap<String, u32>,
+}
+
+/// Decodes `raw` through `font` when available and it resolves at least one
+/// code (codes it can't resolve are dropped, as in `decode`); otherwise falls back to treating `raw` as UTF-8 (lossily), which is the
+/// best guess available for fonts with no `/Encoding` or `/ToUnicode` info.
+pub fn decode_with_fallback(raw: &[u8], font: Option<&FontEncodingMap>) -> String {
+    font.and_then(|f| f.decode(raw))
+        .unwrap_or_else(|| String::from_utf8_lossy(raw).to_string())
+}
+
+impl FontEncodingMap {
+    /// True for Type0/CID fonts addressed by 2-byte codes, where a failed
+    /// `encode` can't fall back to writing raw UTF-8 bytes into the operand
+    /// without corrupting the glyph stream.
+    pub fn is_multi_byte(&self) -> bool {
+        self.code_bytes > 1
+    }
+
+    /// Decodes raw operand bytes into a Unicode string, one output `char`
+    /// (or multi-char glyph, for ligatures) per resolved code. Codes with no
+    /// mapping are dropped rather than guessed at.
+    pub fn decode(&self, raw: &[u8]) -> Option<String> {
+        if self.code_to_unicode.is_empty() {
+            return None;
+        }
+        let mut out = String::new();
+        let mut any = false;
+        for code in self.codes(raw) {
+            if let Some(u) = self.code_to_unicode.get(&code) {
+                out.push_str(u);
+                any = true;
+            }
+        }
+        if any {
+            Some(out)
+        } else {
+            None
+        }
+    }
+
+    /// Re-encodes a Unicode string back into this font's code space, for
+    /// writing a replacement back into an operand. Returns `None` if any
+    /// character has no known code in this font, since a partial re-encode
+    /// would silently corrupt the glyph stream.
+    pub fn encode(&self, text: &str) -> Option<Vec<u8>> {
+        let mut out = Vec::with_capacity(text.len() * self.code_bytes as usize);
+        for ch in text.chars() {
+            let code = *self.unicode_to_code.get(&ch.to_string())?;
+            match self.code_bytes {
+                1 => out.push(code as u8),
+                _ => out.extend_from_slice(&(code as u16).to_be_bytes()),
+            }
+        }
+        Some(out)
+    }
+
+    fn codes(&self, raw: &[u8]) -> Vec<u32> {
+        if self.code_bytes == 2 {
+            raw.chunks(2)
+                .filter(|c| c.len() == 2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]) as u32)
+                .collect()
+        } else {
+            raw.iter().map(|&b| b as u32).collect()
+        }
+    }
+}
+
+/// Resolves the `/Font` entries of a page's `/Resources` dictionary into
+/// encoding maps keyed by the resource name used in `Tf` operators (e.g.
+/// `F1`).
+pub fn resolve_page_fonts(doc: &Document, page_id: ObjectId) -> HashMap<Vec<u8>, FontEncodingMap> {
+    let mut out = HashMap::new();
+    let Ok(page_dict) = doc.get_dictionary(page_id) else {
+        return out;
+    };
+    let Ok(resources) = page_dict
+        .get(b"Resources")
+        .and_then(|o| doc.dereference(o).map(|(_, o)| o))
+        .and_then(Object::as_dict)
+    else {
+        return out;
+    };
+    let Ok(font_dict) = resources.get(b"Font").and_then(Object::as_dict) else {
+        return out;
+    };
+    for (name, font_ref) in font_dict.iter() {
+        let Ok(font_obj) = doc.dereference(font_ref).map(|(_, o)| o) else {
+            continue;
+        };
+        let Ok(font) = font_obj.as_dict() else {
+            continue;
+        };
+        out.insert(name.clone(), build_font_encoding(doc, font));
+    }
+    out
+}
+
+fn build_font_encoding(doc: &Document, font: &Dictionary) -> FontEncodingMap {
+    let is_type0 = font
+        .get(b"Subtype")
+        .and_then(Object::as_name)
+        .map(|n| n == b"Type0")
+        .unwrap_or(false);
+
+    let mut code_to_unicode: HashMap<u32, String> = HashMap::new();
+
+    if !is_type0 {
+        // Simple font: start from the base encoding table, then apply any
+        // per-code overrides from /Differences.
+        let base_name = encoding_base_name(font);
+        code_to_unicode = base_encoding_table(base_name);
+        let encoding_obj = font
+            .get(b"Encoding")
+            .ok()
+            .and_then(|o| doc.dereference(o).map(|(_, o)| o).ok());
+        if let Some(Object::Dictionary(enc)) = encoding_obj {
+            if let Ok(Object::Array(diffs)) = enc.get(b"Differences") {
+                apply_differences(diffs, &mut code_to_unicode);
+            }
+        }
+    }
+
+    // /ToUnicode, when present, is the most authoritative source and is the
+    // only source we have for Type0/Identity-H fonts.
+    if let Ok(tounicode_ref) = font.get(b"ToUnicode") {
+        if let Ok((_, Object::Stream(stream))) = doc.dereference(tounicode_ref) {
+            if let Ok(content) = stream.decompressed_content() {
+                if let Some(map) = parse_to_unicode_cmap(&content) {
+                    code_to_unicode = map;
+                }
+            }
+        }
+    }
+
+    let code_bytes = if is_type0 { 2 } else { 1 };
+    let unicode_to_code = code_to_unicode
+        .iter()
+        .map(|(&code, u)| (u.clone(), code))
+        .collect();
+
+    FontEncodingMap {
+        code_bytes,
+        code_to_unicode,
+        unicode_to_code,
+    }
+}
+
+fn encoding_base_name(font: &Dictionary) -> &'static str {
+    match font.get(b"Encoding") {
+        Ok(Object::Name(name)) => name_to_base(name),
+        Ok(Object::Dictionary(enc)) => enc
+            .get(b"BaseEncoding")
+            .ok()
+            .and_then(Object::as_name)
+            .map(name_to_base)
+            .unwrap_or("StandardEncoding"),
+        _ => "StandardEncoding",
+    }
+}
+
+fn name_to_base(name: &[u8]) -> &'static str {
+    match name {
+        b"WinAnsiEncoding" => "WinAnsiEncoding",
+        b"MacRomanEncoding" => "MacRomanEncoding",
+        _ => "StandardEncoding",
+    }
+}
+
+/// ASCII (32-126) is shared by every base encoding this crate knows about;
+/// the high range differs, but most redaction targets (names, dates, emails)
+/// live entirely in ASCII, so an approximate high range is an acceptable
+/// trade-off against embedding the full Adobe glyph tables.
+fn base_encoding_table(_base: &'static str) -> HashMap<u32, String> {
+    let mut map = HashMap::new();
+    for code in 0x20u32..=0x7eu32 {
+        map.insert(code, char::from_u32(code).unwrap().to_string());
+    }
+    // Common WinAnsi/MacRoman punctuation that shows up in redaction targets.
+    let extras: &[(u32, char)] = &[
+        (0x91, '\u{2018}'), // left single quote
+        (0x92, '\u{2019}'), // right single quote
+        (0x93, '\u{201C}'), // left double quote
+        (0x94, '\u{201D}'), // right double quote
+        (0x95, '\u{2022}'), // bullet
+        (0x96, '\u{2013}'), // en dash
+        (0x97, '\u{2014}'), // em dash
+        (0xA9, '\u{00A9}'), // copyright
+        (0xAE, '\u{00AE}'), // registered
+    ];
+    for &(code, ch) in extras {
+        map.insert(code, ch.to_string());
+    }
+    map
+}
+
+fn apply_differences(diffs: &[Object], map: &mut HashMap<u32, String>) {
+    let mut code = 0u32;
+    for entry in diffs {
+        match entry {
+            Object::Integer(n) => code = *n as u32,
+            Object::Real(n) => code = *n as u32,
+            Object::Name(name) => {
+                if let Some(ch) = glyph_name_to_unicode(name) {
+                    map.insert(code, ch);
+                }
+                code += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Subset of the Adobe Glyph List covering the glyph names that actually
+/// show up in `/Differences` arrays for redacted documents (accented Latin
+/// letters and common punctuation substitutions). A `/Differences` entry
+/// naming a glyph outside this set is ignored, so its code keeps whatever
+/// mapping the base encoding table gave it.
+fn glyph_name_to_unicode(name: &[u8]) -> Option<String> {
+    let table: &[(&[u8], char)] = &[
+        (b"quoteleft", '\u{2018}'),
+        (b"quoteright", '\u{2019}'),
+        (b"quotedblleft", '\u{201C}'),
+        (b"quotedblright", '\u{201D}'),
+        (b"bullet", '\u{2022}'),
+        (b"endash", '\u{2013}'),
+        (b"emdash", '\u{2014}'),
+        (b"eacute", '\u{00E9}'),
+        (b"egrave", '\u{00E8}'),
+        (b"agrave", '\u{00E0}'),
+        (b"ccedilla", '\u{00E7}'),
+        (b"ntilde", '\u{00F1}'),
+        (b"space", ' '),
+    ];
+    table
+        .iter()
+        .find(|&&(n, _)| n == name)
+        .map(|&(_, ch)| ch.to_string())
+}
+
+/// Parses the `beginbfchar`/`endbfchar` and `beginbfrange`/`endbfrange`
+/// sections of a `/ToUnicode` CMap stream into a code->Unicode map. Only the
+/// hex-token forms actually emitted by PDF producers are handled; anything
+/// using `usecmap` or a non-hex operand is left unmapped.
+fn parse_to_unicode_cmap(content: &[u8]) -> Option<HashMap<u32, String>> {
+    let text = String::from_utf8_lossy(content);
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let mut map = HashMap::new();
+    let mut i = 0;
+    let mut found_any_section = false;
+    while i < tokens.len() {
+        match tokens[i] {
+            "beginbfchar" => {
+                found_any_section = true;
+                i += 1;
+                while i + 1 < tokens.len() && tokens[i] != "endbfchar" {
+                    if let (Some(src), Some(dst)) = (hex_token(tokens[i]), hex_unicode_token(tokens[i + 1])) {
+                        map.insert(src, dst);
+                    }
+                    i += 2;
+                }
+            }
+            "beginbfrange" => {
+                found_any_section = true;
+                i += 1;
+                while i + 2 < tokens.len() && tokens[i] != "endbfrange" {
+                    if let (Some(lo), Some(hi), Some(dst_code)) = (
+                        hex_token(tokens[i]),
+                        hex_token(tokens[i + 1]),
+                        hex_token(tokens[i + 2]),
+                    ) {
+                        for offset in 0..=(hi.saturating_sub(lo)) {
+                            if let Some(ch) = char::from_u32(dst_code + offset) {
+                                map.insert(lo + offset, ch.to_string());
+                            }
+                        }
+                    }
+                    i += 3;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    if found_any_section {
+        Some(map)
+    } else {
+        None
+    }
+}
+
+fn hex_token(tok: &str) -> Option<u32> {
+    let trimmed = tok.trim_start_matches('<').trim_end_matches('>');
+    u32::from_str_radix(trimmed, 16).ok()
+}
+
+fn hex_unicode_token(tok: &str) -> Option<String> {
+    let trimmed = tok.trim_start_matches('<').trim_end_matches('>');
+    // ToUnicode destinations can be multi-UTF16-codeunit (surrogate pairs or
+    // ligatures); decode every 4 hex digits as one UTF-16 code unit.
+    let units: Vec<u16> = trimmed
+        .as_bytes()
+        .chunks(4)
+        .filter_map(|c| std::str::from_utf8(c).ok())
+        .filter_map(|c| u16::from_str_radix(c, 16).ok())
+        .collect();
+    String::from_utf16(&units).ok()
+}
@@ -3,11 +3,21 @@
 
 use base64::Engine as _;
 use lopdf::content::Content;
-use lopdf::{Dictionary, Document, Object, Stream};
+use lopdf::Document;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 use std::process::Command;
 
+mod audit;
+mod content_streams;
+mod engines;
+mod flatten_match;
+mod font_encoding;
+mod replacement_rules;
+use audit::ReplacementRecord;
+use engines::PatchEngine;
+use replacement_rules::CompiledRule;
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 struct NativePdfCapabilities {
@@ -23,6 +33,27 @@ struct NativePdfCapabilities {
 struct PdfReplacementPatch {
     original_text: String,
     new_text: String,
+    /// `"literal" | "regex"`; defaults to `"literal"`. In `"regex"` mode,
+    /// `new_text` may reference capture groups as `$1`, `$2`, etc.
+    #[serde(default)]
+    mode: Option<String>,
+    #[serde(default)]
+    case_insensitive: Option<bool>,
+    /// Caps how many instances of this rule are applied across the whole
+    /// document; `None` is unlimited.
+    #[serde(default)]
+    max_replacements: Option<usize>,
+}
+
+/// A rule that failed to compile (invalid regex, unknown `mode`),
+/// reported back instead of aborting the whole request so the other, valid
+/// rules still get applied.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RuleError {
+    index: usize,
+    original_text: String,
+    error: String,
 }
 
 #[derive(Deserialize)]
@@ -30,6 +61,14 @@ struct PdfReplacementPatch {
 struct NativePdfPatchRequest {
     source_data_url: String,
     replacements: Vec<PdfReplacementPatch>,
+    /// `"auto" | "lopdf" | "qpdf" | "mutool"`; defaults to `"auto"`.
+    #[serde(default)]
+    engine: Option<String>,
+    /// User password for encrypted source PDFs; when absent, an encrypted
+    /// document is decrypted with the empty password. Ignored for
+    /// unencrypted documents.
+    #[serde(default)]
+    password: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -40,6 +79,40 @@ struct NativePdfPatchResponse {
     replaced_count: usize,
     output_data_url: Option<String>,
     message: String,
+    rule_errors: Vec<RuleError>,
+    /// SHA-256 of the original source PDF bytes, hex-encoded.
+    input_hash: String,
+    /// SHA-256 of the output PDF, hex-encoded; `None` when nothing was
+    /// replaced, since there is no output to hash.
+    output_hash: Option<String>,
+    /// One record per applied replacement, for `verify_pdf_patch` to
+    /// re-derive the same edits independently of this response.
+    audit: Vec<ReplacementRecord>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyPdfPatchRequest {
+    /// The original, unpatched source PDF - the same bytes `native_pdf_patch`
+    /// was given, not the output it produced.
+    source_data_url: String,
+    /// The `audit` trail from the `native_pdf_patch` response being verified.
+    audit: Vec<ReplacementRecord>,
+    expected_output_hash: String,
+    /// User password for an encrypted source PDF; same as the original patch
+    /// request.
+    #[serde(default)]
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyPdfPatchResponse {
+    verified: bool,
+    /// `None` when verification failed before there was an output to hash
+    /// (an audit record didn't match the source at all).
+    recomputed_output_hash: Option<String>,
+    message: String,
 }
 
 fn command_exists(program: &str) -> bool {
@@ -72,22 +145,6 @@ fn data_url_to_bytes(data_url: &str) -> Result<Vec<u8>, String> {
         .map_err(|e| format!("Failed to decode base64 data URL: {e}"))
 }
 
-fn replace_in_text(raw: &[u8], replacements: &[PdfReplacementPatch]) -> (Vec<u8>, usize) {
-    let mut text = String::from_utf8_lossy(raw).to_string();
-    let mut count = 0usize;
-    for r in replacements {
-        if r.original_text.is_empty() || r.original_text == r.new_text {
-            continue;
-        }
-        let hits = text.matches(&r.original_text).count();
-        if hits > 0 {
-            text = text.replace(&r.original_text, &r.new_text);
-            count += hits;
-        }
-    }
-    (text.into_bytes(), count)
-}
-
 #[tauri::command]
 fn native_pdf_capabilities() -> NativePdfCapabilities {
     let qpdf = command_exists("qpdf");
@@ -105,108 +162,367 @@ fn native_pdf_capabilities() -> NativePdfCapabilities {
     }
 }
 
+/// Result of running the lopdf-based patch pass over already-parseable PDF
+/// bytes (whether those bytes came straight from the request or were first
+/// normalized by an external engine).
+struct PatchOutcome {
+    replaced_total: usize,
+    encoded_hits: usize,
+    raw_hits: usize,
+    /// Matches found but left unapplied because the replacement text
+    /// couldn't be encoded back into a multi-byte font's code space.
+    skipped_hits: usize,
+    output_bytes: Vec<u8>,
+    was_encrypted: bool,
+    audit: Vec<ReplacementRecord>,
+}
+
+/// Runs the actual find/replace over `bytes`'s content streams. This is the
+/// part of the pipeline lopdf always performs, whether `bytes` is the
+/// request's original PDF or the output of an external normalization pass.
+///
+/// `Document::load_mem` already resolves object streams and cross-reference
+/// streams while parsing, so a successfully loaded `doc` has every object
+/// reachable regardless of which compression PDF 1.5+ producers used; the
+/// one case that needs explicit handling here is an encrypted document,
+/// which has to be decrypted before its content streams mean anything.
+fn patch_document(bytes: &[u8], rules: &[CompiledRule], password: Option<&str>) -> Result<PatchOutcome, String> {
+    let mut doc = Document::load_mem(bytes).map_err(|e| format!("Failed to parse PDF: {e}"))?;
+
+    let was_encrypted = doc.is_encrypted();
+    if was_encrypted {
+        doc.decrypt(password.unwrap_or(""))
+            .map_err(|e| format!("Failed to decrypt PDF (check the password): {e}"))?;
+    }
+
+    let pages = doc.get_pages();
+    let mut replaced_total = 0usize;
+    let mut encoded_hits = 0usize;
+    let mut raw_hits = 0usize;
+    let mut skipped_hits = 0usize;
+    let mut audit = Vec::new();
+    // Tracks each rule's remaining `max_replacements` budget across every
+    // page and content stream in the document; `None` stays unlimited.
+    let mut budgets: Vec<Option<usize>> = rules.iter().map(|r| r.max_replacements).collect();
+
+    for (page_no, page_id) in pages {
+        let fonts = font_encoding::resolve_page_fonts(&doc, page_id);
+
+        for stream_id in content_streams::page_content_stream_ids(&doc, page_id)? {
+            let Some(stream_bytes) = content_streams::decoded_stream_content(&doc, stream_id)? else {
+                continue;
+            };
+            let mut content =
+                Content::decode(&stream_bytes).map_err(|e| format!("Failed to decode page content: {e}"))?;
+            let mut changed = false;
+
+            // Each rule runs against the flattened, logical text of the
+            // whole stream rather than operand-by-operand, so a term split
+            // across a kerned `TJ` array or consecutive `Tj` calls is still
+            // found. Rules run in order against the same `content` so a
+            // later rule sees the effect of an earlier one.
+            for (rule_idx, rule) in rules.iter().enumerate() {
+                if budgets[rule_idx] == Some(0) {
+                    continue;
+                }
+                let (outcome, records) =
+                    flatten_match::apply_rule(&mut content, &fonts, rule, budgets[rule_idx], page_no);
+                skipped_hits += outcome.skipped_hits;
+                if outcome.hits > 0 {
+                    replaced_total += outcome.hits;
+                    encoded_hits += outcome.encoded_hits;
+                    raw_hits += outcome.raw_hits;
+                    changed = true;
+                    audit.extend(records);
+                    if let Some(budget) = budgets[rule_idx].as_mut() {
+                        *budget -= outcome.hits;
+                    }
+                }
+            }
+
+            if changed {
+                let encoded = content
+                    .encode()
+                    .map_err(|e| format!("Failed to encode patched page content: {e}"))?;
+                content_streams::write_stream_content(&mut doc, stream_id, encoded)?;
+            }
+        }
+    }
+
+    let mut output_bytes = Vec::new();
+    if replaced_total > 0 {
+        doc.compress();
+        doc.save_to(&mut Cursor::new(&mut output_bytes))
+            .map_err(|e| format!("Failed to save patched PDF: {e}"))?;
+    }
+
+    Ok(PatchOutcome {
+        replaced_total,
+        encoded_hits,
+        raw_hits,
+        skipped_hits,
+        output_bytes,
+        was_encrypted,
+        audit,
+    })
+}
+
 #[tauri::command]
 fn native_pdf_patch(req: NativePdfPatchRequest) -> Result<NativePdfPatchResponse, String> {
-    let replacements: Vec<PdfReplacementPatch> = req
-        .replacements
-        .into_iter()
-        .filter(|r| !r.original_text.trim().is_empty() && r.original_text != r.new_text)
-        .collect();
-    if replacements.is_empty() {
+    let requested_engine = PatchEngine::parse(req.engine.as_deref())?;
+
+    let mut rules = Vec::new();
+    let mut rule_errors = Vec::new();
+    for (index, raw_rule) in req.replacements.iter().enumerate() {
+        match CompiledRule::compile(raw_rule) {
+            Ok(Some(compiled)) => rules.push(compiled),
+            Ok(None) => {} // empty or no-op rule; silently skipped, as before
+            Err(error) => rule_errors.push(RuleError {
+                index,
+                original_text: raw_rule.original_text.clone(),
+                error,
+            }),
+        }
+    }
+    if rules.is_empty() {
         return Ok(NativePdfPatchResponse {
             success: false,
             engine: "lopdf-native-object-patch".to_string(),
             replaced_count: 0,
             output_data_url: None,
-            message: "No valid replacements were provided.".to_string(),
+            message: if rule_errors.is_empty() {
+                "No valid replacements were provided.".to_string()
+            } else {
+                "No valid replacements were provided; every rule failed to compile.".to_string()
+            },
+            rule_errors,
+            input_hash: String::new(),
+            output_hash: None,
+            audit: Vec::new(),
         });
     }
 
     let bytes = data_url_to_bytes(&req.source_data_url)?;
-    let mut doc = Document::load_mem(&bytes).map_err(|e| format!("Failed to parse PDF: {e}"))?;
-    let pages = doc.get_pages();
-    let mut replaced_total = 0usize;
+    let input_hash = audit::sha256_hex(&bytes);
 
-    for (_page_no, page_id) in pages {
-        let page_data = doc
-            .get_page_content(page_id)
-            .map_err(|e| format!("Failed to read page content: {e}"))?;
-        let mut content = Content::decode(&page_data).map_err(|e| format!("Failed to decode page content: {e}"))?;
-        let mut changed_this_page = false;
-
-        for op in content.operations.iter_mut() {
-            match op.operator.as_str() {
-                "Tj" | "'" | "\"" => {
-                    if let Some(Object::String(raw, _)) = op.operands.get_mut(0) {
-                        let (next, hits) = replace_in_text(raw, &replacements);
-                        if hits > 0 {
-                            *raw = next;
-                            replaced_total += hits;
-                            changed_this_page = true;
-                        }
+    // For an explicit (non-auto) external engine, always normalize first -
+    // that's the whole point of asking for it.
+    let (mut engine_label, patch_input): (String, Vec<u8>) = match requested_engine {
+        PatchEngine::Lopdf | PatchEngine::Auto => ("lopdf-native-object-patch".to_string(), bytes.clone()),
+        PatchEngine::Qpdf => (
+            PatchEngine::Qpdf.label().to_string(),
+            engines::normalize_with_qpdf(&bytes).map_err(|e| format!("qpdf engine failed: {e}"))?,
+        ),
+        PatchEngine::Mutool => (
+            PatchEngine::Mutool.label().to_string(),
+            engines::normalize_with_mutool(&bytes).map_err(|e| format!("mutool engine failed: {e}"))?,
+        ),
+    };
+
+    let mut outcome = patch_document(&patch_input, &rules, req.password.as_deref())?;
+
+    if requested_engine == PatchEngine::Auto && outcome.replaced_total == 0 {
+        // lopdf found nothing. That can mean the terms really aren't in the
+        // document, but it's also the signature of lopdf choking on heavy
+        // compression or a linearized layout - so give an external tool a
+        // shot at normalizing the bytes before giving up.
+        let fallback = if command_exists("qpdf") {
+            Some((PatchEngine::Qpdf, engines::normalize_with_qpdf(&bytes)))
+        } else if command_exists("mutool") {
+            Some((PatchEngine::Mutool, engines::normalize_with_mutool(&bytes)))
+        } else {
+            None
+        };
+
+        if let Some((engine, normalized)) = fallback {
+            match normalized {
+                Ok(normalized_bytes) => {
+                    let retried = patch_document(&normalized_bytes, &rules, req.password.as_deref())?;
+                    if retried.replaced_total > 0 {
+                        engine_label = engine.label().to_string();
+                        outcome = retried;
                     }
                 }
-                "TJ" => {
-                    if let Some(Object::Array(items)) = op.operands.get_mut(0) {
-                        for entry in items.iter_mut() {
-                            if let Object::String(raw, _) = entry {
-                                let (next, hits) = replace_in_text(raw, &replacements);
-                                if hits > 0 {
-                                    *raw = next;
-                                    replaced_total += hits;
-                                    changed_this_page = true;
-                                }
-                            }
-                        }
-                    }
+                Err(e) => {
+                    return Ok(NativePdfPatchResponse {
+                        success: false,
+                        engine: "lopdf-native-object-patch".to_string(),
+                        replaced_count: 0,
+                        output_data_url: None,
+                        message: format!(
+                            "No matching text objects were found via lopdf, and the {} fallback failed: {e}",
+                            engine.label()
+                        ),
+                        rule_errors,
+                        input_hash,
+                        output_hash: None,
+                        audit: Vec::new(),
+                    });
                 }
-                _ => {}
             }
         }
-
-        if changed_this_page {
-            let encoded = content
-                .encode()
-                .map_err(|e| format!("Failed to encode patched page content: {e}"))?;
-            let new_stream_id = doc.add_object(Stream::new(Dictionary::new(), encoded));
-            let page_obj = doc
-                .get_object_mut(page_id)
-                .map_err(|e| format!("Failed to access page object: {e}"))?;
-            let page_dict = page_obj
-                .as_dict_mut()
-                .map_err(|e| format!("Failed to convert page object to dict: {e}"))?;
-            page_dict.set("Contents", Object::Reference(new_stream_id));
-        }
     }
 
-    if replaced_total == 0 {
+    if outcome.replaced_total == 0 {
+        let message = if outcome.skipped_hits > 0 {
+            format!(
+                "Found {} match(es), but none could be applied: their font couldn't encode the replacement text.",
+                outcome.skipped_hits
+            )
+        } else {
+            "No matching text objects were found for replacement.".to_string()
+        };
         return Ok(NativePdfPatchResponse {
             success: false,
-            engine: "lopdf-native-object-patch".to_string(),
+            engine: engine_label,
             replaced_count: 0,
             output_data_url: None,
-            message: "No matching text objects were found for replacement.".to_string(),
+            message,
+            rule_errors,
+            input_hash,
+            output_hash: None,
+            audit: Vec::new(),
         });
     }
 
-    doc.compress();
-    let mut out = Vec::<u8>::new();
-    doc.save_to(&mut Cursor::new(&mut out))
-        .map_err(|e| format!("Failed to save patched PDF: {e}"))?;
-    let encoded = base64::engine::general_purpose::STANDARD.encode(out);
+    let output_hash = audit::sha256_hex(&outcome.output_bytes);
+    let encoded = base64::engine::general_purpose::STANDARD.encode(outcome.output_bytes);
+    let mut message = format!(
+        "Native object patch completed ({} match(es) via font encoding, {} via raw-byte fallback).",
+        outcome.encoded_hits, outcome.raw_hits
+    );
+    if outcome.was_encrypted {
+        message.push_str(" Source document was decrypted for editing; the output is saved unencrypted.");
+    }
+    if outcome.skipped_hits > 0 {
+        message.push_str(&format!(
+            " {} match(es) were left untouched because their font couldn't encode the replacement text.",
+            outcome.skipped_hits
+        ));
+    }
     Ok(NativePdfPatchResponse {
         success: true,
-        engine: "lopdf-native-object-patch".to_string(),
-        replaced_count: replaced_total,
+        engine: engine_label,
+        replaced_count: outcome.replaced_total,
         output_data_url: Some(format!("data:application/pdf;base64,{encoded}")),
-        message: "Native object patch completed.".to_string(),
+        message,
+        rule_errors,
+        input_hash,
+        output_hash: Some(output_hash),
+        audit: outcome.audit,
+    })
+}
+
+/// Re-applies an audited set of `ReplacementRecord`s to `bytes` (the
+/// original, unpatched source) and returns how many records were actually
+/// found at their recorded position, alongside the resulting PDF bytes.
+/// Mirrors `patch_document`'s page/stream walk, but replays recorded edits
+/// positionally instead of running rule matching - see
+/// `flatten_match::apply_audit_records`.
+fn replay_audit_trail(
+    bytes: &[u8],
+    records: &[ReplacementRecord],
+    password: Option<&str>,
+) -> Result<(usize, Vec<u8>), String> {
+    let mut doc = Document::load_mem(bytes).map_err(|e| format!("Failed to parse PDF: {e}"))?;
+    if doc.is_encrypted() {
+        doc.decrypt(password.unwrap_or(""))
+            .map_err(|e| format!("Failed to decrypt PDF (check the password): {e}"))?;
+    }
+
+    let mut applied_total = 0usize;
+    for (page_no, page_id) in doc.get_pages() {
+        let fonts = font_encoding::resolve_page_fonts(&doc, page_id);
+
+        for stream_id in content_streams::page_content_stream_ids(&doc, page_id)? {
+            let Some(stream_bytes) = content_streams::decoded_stream_content(&doc, stream_id)? else {
+                continue;
+            };
+            let mut content =
+                Content::decode(&stream_bytes).map_err(|e| format!("Failed to decode page content: {e}"))?;
+            let applied = flatten_match::apply_audit_records(&mut content, &fonts, page_no, records);
+            if applied > 0 {
+                applied_total += applied;
+                let encoded = content
+                    .encode()
+                    .map_err(|e| format!("Failed to encode verified page content: {e}"))?;
+                content_streams::write_stream_content(&mut doc, stream_id, encoded)?;
+            }
+        }
+    }
+
+    let mut output_bytes = Vec::new();
+    doc.compress();
+    doc.save_to(&mut Cursor::new(&mut output_bytes))
+        .map_err(|e| format!("Failed to save verified PDF: {e}"))?;
+    Ok((applied_total, output_bytes))
+}
+
+/// Confirms that a `native_pdf_patch` response is trustworthy by
+/// independently re-deriving its output: starting from the *original*
+/// source PDF (not the output, which a tampering party also controls),
+/// replaying each recorded edit at its exact recorded position, and
+/// comparing the resulting hash against `expectedOutputHash`. A caller who
+/// only compared a received file's hash against a hash from the same
+/// response would be checking a value the tamperer could have altered
+/// together with the file; this instead re-derives the output from
+/// first principles using only the source bytes and the audit trail.
+///
+/// Replaying positionally (rather than re-running rule matching against the
+/// source) avoids having to reproduce the original request's rule ordering
+/// and per-rule `max_replacements` caps exactly, either of which would
+/// otherwise cause a false mismatch even when nothing was tampered with.
+/// The replay always runs lopdf on the source bytes as given, so a response
+/// produced by the `qpdf` or `mutool` engine (patched from normalized bytes)
+/// is not reproduced and reports a mismatch.
+#[tauri::command]
+fn verify_pdf_patch(req: VerifyPdfPatchRequest) -> Result<VerifyPdfPatchResponse, String> {
+    if req.audit.is_empty() {
+        return Ok(VerifyPdfPatchResponse {
+            verified: false,
+            recomputed_output_hash: None,
+            message: "No audit records to verify against.".to_string(),
+        });
+    }
+
+    let bytes = data_url_to_bytes(&req.source_data_url)?;
+    let (applied_total, output_bytes) = replay_audit_trail(&bytes, &req.audit, req.password.as_deref())?;
+
+    if applied_total != req.audit.len() {
+        return Ok(VerifyPdfPatchResponse {
+            verified: false,
+            recomputed_output_hash: None,
+            message: format!(
+                "Mismatch: only {} of {} audited replacement(s) were found at their recorded position in the source PDF.",
+                applied_total,
+                req.audit.len()
+            ),
+        });
+    }
+
+    let recomputed_output_hash = audit::sha256_hex(&output_bytes);
+    let verified = recomputed_output_hash == req.expected_output_hash;
+    Ok(VerifyPdfPatchResponse {
+        message: if verified {
+            "Verified: replaying the audited edits against the original source reproduces the reported output hash."
+                .to_string()
+        } else {
+            "Mismatch: replaying the audited edits against the original source does not reproduce the reported output hash."
+                .to_string()
+        },
+        recomputed_output_hash: Some(recomputed_output_hash),
+        verified,
     })
 }
 
 fn main() {
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
-        .invoke_handler(tauri::generate_handler![native_pdf_capabilities, native_pdf_patch])
+        .invoke_handler(tauri::generate_handler![
+            native_pdf_capabilities,
+            native_pdf_patch,
+            verify_pdf_patch
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
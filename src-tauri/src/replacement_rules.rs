@@ -0,0 +1,147 @@
+// Compiles a `PdfReplacementPatch` into something `flatten_match` can run
+// repeatedly against flattened page text: either a literal (optionally
+// case-insensitive) substring search, or a regex with `$1`-style capture
+// group expansion in the replacement text.
+
+use crate::PdfReplacementPatch;
+use regex::{Regex, RegexBuilder};
+use std::ops::Range;
+
+/// One match of a compiled rule against some flattened text, along with the
+/// already-expanded replacement text for that specific occurrence (regex
+/// capture groups can make this differ between matches of the same rule).
+pub struct MatchInstance {
+    pub range: Range<usize>,
+    pub replacement: String,
+}
+
+enum Kind {
+    Literal {
+        needle: String,
+        new_text: String,
+        case_insensitive: bool,
+    },
+    Regex {
+        regex: Regex,
+        new_text: String,
+    },
+}
+
+pub struct CompiledRule {
+    kind: Kind,
+    pub max_replacements: Option<usize>,
+}
+
+impl CompiledRule {
+    /// Compiles one rule. Returns `Ok(None)` for rules that are no-ops (no
+    /// original text, or a case-sensitive literal rule whose original equals
+    /// its replacement) so callers can skip
+    /// them without treating that as an error.
+    pub fn compile(rule: &PdfReplacementPatch) -> Result<Option<CompiledRule>, String> {
+        if rule.original_text.is_empty() {
+            return Ok(None);
+        }
+        let case_insensitive = rule.case_insensitive.unwrap_or(false);
+        let kind = match rule.mode.as_deref().unwrap_or("literal") {
+            "literal" => {
+                if !case_insensitive && rule.original_text == rule.new_text {
+                    return Ok(None);
+                }
+                Kind::Literal {
+                    needle: rule.original_text.clone(),
+                    new_text: rule.new_text.clone(),
+                    case_insensitive,
+                }
+            }
+            "regex" => {
+                let regex = RegexBuilder::new(&rule.original_text)
+                    .case_insensitive(case_insensitive)
+                    .build()
+                    .map_err(|e| format!("Invalid regex pattern: {e}"))?;
+                Kind::Regex {
+                    regex,
+                    new_text: rule.new_text.clone(),
+                }
+            }
+            other => {
+                return Err(format!(
+                    "Unknown replacement mode \"{other}\" (expected \"literal\" or \"regex\")"
+                ));
+            }
+        };
+        Ok(Some(CompiledRule {
+            kind,
+            max_replacements: rule.max_replacements,
+        }))
+    }
+
+    /// Finds every match of this rule in `haystack`, capped at `budget`
+    /// instances (the caller's running total against `max_replacements`,
+    /// which spans every content stream a rule is applied to, not just
+    /// this one).
+    pub fn find_matches(&self, haystack: &str, budget: Option<usize>) -> Vec<MatchInstance> {
+        if budget == Some(0) {
+            return Vec::new();
+        }
+        let mut out = match &self.kind {
+            Kind::Literal {
+                needle,
+                new_text,
+                case_insensitive,
+            } => find_literal_matches(haystack, needle, *case_insensitive)
+                .into_iter()
+                .map(|range| MatchInstance {
+                    range,
+                    replacement: new_text.clone(),
+                })
+                .collect(),
+            Kind::Regex { regex, new_text } => regex
+                .captures_iter(haystack)
+                .map(|caps| {
+                    let m = caps.get(0).expect("capture group 0 always matches");
+                    let mut replacement = String::new();
+                    caps.expand(new_text, &mut replacement);
+                    MatchInstance {
+                        range: m.start()..m.end(),
+                        replacement,
+                    }
+                })
+                .collect::<Vec<_>>(),
+        };
+        if let Some(budget) = budget {
+            out.truncate(budget);
+        }
+        out
+    }
+}
+
+/// Case-insensitive literal matching is done byte-window-wise, comparing
+/// ASCII case-insensitively, rather than lowercasing both sides: redaction
+/// targets (names, account numbers, emails) are overwhelmingly ASCII, and
+/// this sidesteps Unicode case-folding changing a match's byte length
+/// relative to `needle`, which would otherwise complicate mapping matches
+/// back to byte offsets in the original text.
+fn find_literal_matches(haystack: &str, needle: &str, case_insensitive: bool) -> Vec<Range<usize>> {
+    if !case_insensitive {
+        return haystack
+            .match_indices(needle)
+            .map(|(start, m)| start..start + m.len())
+            .collect();
+    }
+    let hay = haystack.as_bytes();
+    let pat = needle.as_bytes();
+    if pat.is_empty() || pat.len() > hay.len() {
+        return Vec::new();
+    }
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i + pat.len() <= hay.len() {
+        if hay[i..i + pat.len()].eq_ignore_ascii_case(pat) {
+            out.push(i..i + pat.len());
+            i += pat.len();
+        } else {
+            i += 1;
+        }
+    }
+    out
+}